@@ -0,0 +1,137 @@
+use anyhow::Context;
+use camino::Utf8Path;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
+
+/// Ownership/permissions to apply to a file, but only if the file doesn't already exist.
+///
+/// When the destination already exists, its owner/group/permissions are left untouched so that a
+/// rewrite of, say, `/etc/tedge/tedge.toml` doesn't clobber permissions an admin set by hand.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MaybePermissions {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub mode: Option<u32>,
+}
+
+/// Writes the contents of `src` to `dest` atomically, by writing to a temporary file in the same
+/// directory and renaming it into place.
+///
+/// If `dest` doesn't already exist, `permissions` is applied to the temporary file *before* the
+/// rename, so the destination is never momentarily visible with the default `0o666 & !umask`
+/// permissions a freshly-created file would otherwise have. If `dest` already exists, its
+/// owner/group/permissions are left unchanged.
+pub fn write_file_atomic_set_permissions_if_doesnt_exist(
+    mut src: impl Read,
+    dest: &Utf8Path,
+    permissions: &MaybePermissions,
+) -> anyhow::Result<()> {
+    let existed = dest.exists();
+
+    let dir = dest
+        .parent()
+        .context("destination path has no parent directory")?;
+
+    let mut temp_file =
+        tempfile::NamedTempFile::new_in(dir).context("failed to create temporary file")?;
+
+    std::io::copy(&mut src, &mut temp_file).context("failed to write to temporary file")?;
+    temp_file
+        .flush()
+        .context("failed to flush temporary file")?;
+
+    if existed {
+        // The destination already exists: preserve its owner/mode across the rename rather than
+        // letting the temp file's own (root-owned, 0o600) defaults replace them.
+        let existing_meta = std::fs::metadata(dest)
+            .with_context(|| format!("failed to stat existing destination '{dest}'"))?;
+
+        std::fs::set_permissions(
+            temp_file.path(),
+            std::fs::Permissions::from_mode(existing_meta.mode()),
+        )
+        .context("failed to carry over existing permissions onto temporary file")?;
+
+        std::os::unix::fs::chown(
+            temp_file.path(),
+            Some(existing_meta.uid()),
+            Some(existing_meta.gid()),
+        )
+        .context("failed to carry over existing ownership onto temporary file")?;
+    } else {
+        if let Some(mode) = permissions.mode {
+            std::fs::set_permissions(temp_file.path(), std::fs::Permissions::from_mode(mode))
+                .with_context(|| format!("failed to set permissions {mode:o} on temporary file"))?;
+        }
+
+        if permissions.uid.is_some() || permissions.gid.is_some() {
+            std::os::unix::fs::chown(temp_file.path(), permissions.uid, permissions.gid)
+                .with_context(|| {
+                    format!(
+                        "failed to change ownership {:?}:{:?} on temporary file",
+                        permissions.uid, permissions.gid
+                    )
+                })?;
+        }
+    }
+
+    // The mode/owner above were applied before this rename, so the destination is never visible
+    // with the temp file's default permissions.
+    temp_file
+        .persist(dest)
+        .map_err(|err| err.error)
+        .with_context(|| format!("failed to persist temporary file to '{dest}'"))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_file_gets_requested_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = Utf8Path::from_path(dir.path()).unwrap().join("file");
+        let permissions = MaybePermissions {
+            uid: None,
+            gid: None,
+            mode: Some(0o640),
+        };
+
+        write_file_atomic_set_permissions_if_doesnt_exist(&b"hello"[..], &dest, &permissions)
+            .unwrap();
+
+        let mode = std::fs::metadata(&dest).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o640);
+        assert_eq!(std::fs::read(&dest).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn existing_file_keeps_its_permissions_and_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = Utf8Path::from_path(dir.path()).unwrap().join("file");
+        std::fs::write(&dest, b"old").unwrap();
+        std::fs::set_permissions(&dest, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let existing_meta = std::fs::metadata(&dest).unwrap();
+
+        // Request very different permissions: since the file already exists, these must be
+        // ignored in favor of what's already there.
+        let permissions = MaybePermissions {
+            uid: None,
+            gid: None,
+            mode: Some(0o777),
+        };
+
+        write_file_atomic_set_permissions_if_doesnt_exist(&b"new"[..], &dest, &permissions)
+            .unwrap();
+
+        let new_meta = std::fs::metadata(&dest).unwrap();
+        assert_eq!(new_meta.permissions().mode() & 0o777, 0o640);
+        assert_eq!(new_meta.uid(), existing_meta.uid());
+        assert_eq!(new_meta.gid(), existing_meta.gid());
+        assert_eq!(std::fs::read(&dest).unwrap(), b"new");
+    }
+}
@@ -2,8 +2,10 @@
 
 use anyhow::bail;
 use anyhow::Context;
+use camino::Utf8Path;
 use camino::Utf8PathBuf;
 use clap::Parser;
+use glob::Pattern;
 use tedge_config::cli::CommonArgs;
 use tedge_config::log_init;
 use tedge_utils::atomic::MaybePermissions;
@@ -22,7 +24,8 @@ pub struct Args {
     /// unchanged.
     destination_path: Utf8PathBuf,
 
-    /// Permission mode for the file, in octal form.
+    /// Permission mode for the file, either in octal form (e.g. "644") or as a chmod-style
+    /// symbolic expression (e.g. "u=rw,g=r,o=" or "u+x,g-w").
     #[arg(long)]
     mode: Option<Box<str>>,
 
@@ -34,15 +37,395 @@ pub struct Args {
     #[arg(long)]
     group: Option<Box<str>>,
 
-    /// Use to create intermediate paths when needed. 
-    /// Created paths will have the permission 0755 and owner as specified by --user and --group.
+    /// Use to create intermediate paths when needed.
+    /// Created paths will have the permission 0755 (unless overridden with --dir-mode) and owner
+    /// as specified by --user and --group.
     #[arg(long, default_value_t = false)]
     makedirs: bool,
 
+    /// Permission mode for directories created by --makedirs, either in octal form or as a
+    /// chmod-style symbolic expression (see --mode). Defaults to 0755 when unset.
+    #[arg(long)]
+    dir_mode: Option<Box<str>>,
+
+    /// Append standard input to the destination file instead of atomically replacing it.
+    ///
+    /// If the file does not exist, it is created with the specified owner/group/permissions, same
+    /// as without --append. If it does exist, its owner/group/permissions remain unchanged and
+    /// standard input is appended to its current contents.
+    #[arg(long, default_value_t = false)]
+    append: bool,
+
+    /// Enables a hardening check which requires every ancestor directory of `destination_path`,
+    /// from `/` down to its parent, to be owned by root or the given uid, and to not be
+    /// group/other-writable unless the group is the trusted --trust-gid or the directory has the
+    /// sticky bit set (e.g. /tmp).
+    ///
+    /// This closes a hole that the `..`/canonicalization check alone does not: if an intermediate
+    /// directory is writable by an untrusted user, that user can swap it out (or relax its
+    /// permissions) to redirect a privileged write performed through a sudoers rule like
+    /// `tedge ALL=(ALL) NOPASSWD: /usr/bin/tedge-write /etc/*`.
+    #[arg(long)]
+    trust_uid: Option<u32>,
+
+    /// Group trusted to hold write access to ancestor directories, used together with
+    /// --trust-uid. See --trust-uid for details.
+    #[arg(long, requires = "trust_uid")]
+    trust_gid: Option<u32>,
+
+    /// Resolve the destination path if it is a symlink, instead of refusing to write to it.
+    ///
+    /// Without this flag, a destination which exists but is not a regular file (a symlink, FIFO,
+    /// device node, etc.) is rejected: writing through it could act on an inode outside of the
+    /// directory the caller was meant to be restricted to, or on a device shared with other
+    /// processes.
+    #[arg(long, default_value_t = false)]
+    follow_symlinks: bool,
+
+    /// Instead of writing standard input to `destination_path`, recursively apply --mode/--user/
+    /// --group to `destination_path` (which must already be an existing directory) and every
+    /// entry underneath it.
+    #[arg(long, default_value_t = false)]
+    recursive: bool,
+
+    /// Glob pattern, relative to `destination_path`, to skip when applying --recursive. Can be
+    /// given multiple times.
+    #[arg(long = "exclude")]
+    exclude: Vec<String>,
+
     #[command(flatten)]
     common: CommonArgs,
 }
 
+/// Verifies that every ancestor directory of `target_filepath`, from `/` down to its parent, is
+/// owned by root or `trust_uid`, and does not grant write access to an untrusted group or to
+/// everyone. Directories with the sticky bit set are exempt from the write-access check.
+///
+/// This mirrors the ancestor-verification logic used by fs-mistrust style crates: a writable or
+/// attacker-owned intermediate directory would let an attacker redirect a privileged write even
+/// though the destination path itself is canonical.
+fn verify_ancestors_trusted(
+    target_filepath: &Utf8Path,
+    trust_uid: u32,
+    trust_gid: Option<u32>,
+) -> anyhow::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let parent = target_filepath
+        .parent()
+        .context("destination path has no parent directory")?;
+
+    let mut failures = Vec::new();
+    let mut current = Utf8PathBuf::new();
+    for component in parent.components() {
+        current.push(component);
+
+        let meta = match std::fs::symlink_metadata(&current) {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                // Everything up to here has already been verified; the rest of the chain
+                // doesn't exist yet and will be created by --makedirs (which applies its own
+                // trusted owner/mode to each directory it creates), so there's nothing left
+                // to check.
+                break;
+            }
+            Err(err) => return Err(err).with_context(|| format!("failed to stat '{current}'")),
+        };
+
+        let uid = meta.uid();
+        if uid != 0 && uid != trust_uid {
+            failures.push(format!(
+                "'{current}' has bad owner: uid {uid} (expected 0 or {trust_uid})"
+            ));
+            continue;
+        }
+
+        let mut forbidden = 0o022;
+        if let Some(trust_gid) = trust_gid {
+            if meta.gid() == trust_gid {
+                forbidden &= !0o070;
+            }
+        }
+
+        // A sticky directory (e.g. /tmp) only lets other users create files, not rename/delete
+        // ones they don't own, so a writable group/other there can't be used to swap out this
+        // ancestor the way a plain writable directory could.
+        const STICKY: u32 = 0o1000;
+        if meta.mode() & STICKY != 0 {
+            forbidden &= !0o022;
+        }
+
+        let mode = meta.mode();
+        if mode & forbidden != 0 {
+            failures.push(format!(
+                "'{current}' has bad permissions: {:o} conflicts with forbidden bits {:o}",
+                mode & 0o7777,
+                forbidden
+            ));
+        }
+    }
+
+    if !failures.is_empty() {
+        bail!(
+            "refusing to write: untrusted ancestor director{}:\n{}",
+            if failures.len() == 1 { "y" } else { "ies" },
+            failures.join("\n")
+        );
+    }
+
+    Ok(())
+}
+
+/// Refuses to write to `target_filepath` if it already exists and is not a regular file.
+///
+/// A symlink, FIFO, or device node (`/dev/null`, `/dev/stdout`, ...) at the destination could
+/// redirect the write to an inode outside of the directory the sudoers rule was meant to restrict
+/// us to, or to a device shared with other processes. If `follow_symlinks` is set and the
+/// destination is a symlink, it is resolved and re-canonicalized instead of being rejected — but
+/// the regular-file check is then re-run on the resolved target, since a symlink can point at a
+/// FIFO or device node just as easily as at a regular file.
+fn refuse_non_regular_destination(
+    mut target_filepath: Utf8PathBuf,
+    follow_symlinks: bool,
+) -> anyhow::Result<Utf8PathBuf> {
+    loop {
+        let meta = match std::fs::symlink_metadata(&target_filepath) {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(target_filepath),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("failed to stat destination '{target_filepath}'"))
+            }
+        };
+
+        if meta.is_file() {
+            return Ok(target_filepath);
+        }
+
+        if follow_symlinks && meta.file_type().is_symlink() {
+            let resolved = std::fs::canonicalize(&target_filepath)
+                .with_context(|| format!("failed to resolve symlink '{target_filepath}'"))?;
+            target_filepath = resolved
+                .try_into()
+                .context("resolved destination path is not valid UTF-8")?;
+            continue;
+        }
+
+        bail!(
+            "Destination '{target_filepath}' already exists and is not a regular file ({:?}); \
+             refusing to write to it (use --follow-symlinks to resolve symlinks)",
+            meta.file_type()
+        );
+    }
+}
+
+/// Parses a permission mode spec, which is either plain octal (e.g. "644") or a comma-separated
+/// list of chmod-style symbolic clauses (e.g. "u=rw,g=r,o=" or "u+x,g-w").
+///
+/// Symbolic clauses are applied left-to-right on top of `base`, which should be `0` for a new
+/// file or the existing file's mode for incremental `+`/`-` clauses. `is_dir` controls how the
+/// symbolic `X` permission behaves: it sets execute only if the target is a directory or if any
+/// execute bit is already set in the mode accumulated so far.
+fn parse_mode(spec: &str, base: u32, is_dir: bool) -> anyhow::Result<u32> {
+    if let Ok(mode) = u32::from_str_radix(spec, 8) {
+        return Ok(mode);
+    }
+
+    parse_symbolic_mode(spec, base, is_dir).with_context(|| format!("invalid mode: '{spec}'"))
+}
+
+fn parse_symbolic_mode(spec: &str, base: u32, is_dir: bool) -> anyhow::Result<u32> {
+    let mut mode = base;
+    for clause in spec.split(',') {
+        mode = apply_symbolic_clause(clause, mode, is_dir)
+            .with_context(|| format!("invalid clause '{clause}'"))?;
+    }
+    Ok(mode)
+}
+
+fn apply_symbolic_clause(clause: &str, mode: u32, is_dir: bool) -> anyhow::Result<u32> {
+    let op_index = clause
+        .find(['=', '+', '-'])
+        .context("expected one of '=', '+', '-'")?;
+    let (who, rest) = clause.split_at(op_index);
+    let op = rest.as_bytes()[0] as char;
+    let perms = &rest[1..];
+
+    let who = if who.is_empty() { "a" } else { who };
+    if who.chars().any(|c| !matches!(c, 'u' | 'g' | 'o' | 'a')) {
+        bail!("'{who}' is not a valid combination of u, g, o, a");
+    }
+
+    let has_any_exec = mode & 0o111 != 0;
+    let mut perm_bits = 0u32;
+    for c in perms.chars() {
+        perm_bits |= match c {
+            'r' => 0b100,
+            'w' => 0b010,
+            'x' => 0b001,
+            'X' if is_dir || has_any_exec => 0b001,
+            'X' => 0b000,
+            other => bail!("'{other}' is not a valid permission (expected r, w, x or X)"),
+        };
+    }
+
+    let mut mode = mode;
+    for who_char in who.chars() {
+        let shifts: &[u32] = match who_char {
+            'u' => &[6],
+            'g' => &[3],
+            'o' => &[0],
+            'a' => &[6, 3, 0],
+            _ => unreachable!(),
+        };
+        for &shift in shifts {
+            let mask = 0b111 << shift;
+            mode = match op {
+                '=' => (mode & !mask) | (perm_bits << shift),
+                '+' => mode | (perm_bits << shift),
+                '-' => mode & !(perm_bits << shift),
+                _ => unreachable!(),
+            };
+        }
+    }
+
+    Ok(mode)
+}
+
+/// Recursively applies --mode/--user/--group to `root` and every entry underneath it, skipping
+/// subtrees which match any of the `excludes` globs (matched against the entry's path relative to
+/// `root`) and skipping symlinks entirely. Per-path failures are collected and reported together
+/// rather than aborting on the first error, since a single bad entry (e.g. a permission we don't
+/// have) shouldn't stop the whole directory from being brought into the desired state.
+fn apply_recursive(
+    root: &Utf8Path,
+    mode_spec: Option<&str>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    excludes: &[String],
+) -> anyhow::Result<()> {
+    let patterns = excludes
+        .iter()
+        .map(|pattern| {
+            Pattern::new(pattern).with_context(|| format!("invalid --exclude pattern: '{pattern}'"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut entries = vec![(root.to_owned(), true)];
+    let mut failures = Vec::new();
+    collect_entries_recursive(root, root, &patterns, &mut entries, &mut failures);
+
+    for (path, is_dir) in entries {
+        if let Err(err) = apply_owner_and_mode(&path, is_dir, mode_spec, uid, gid) {
+            failures.push(format!("{path}: {err:#}"));
+        }
+    }
+
+    if !failures.is_empty() {
+        bail!(
+            "failed to apply --recursive to the following path{}:\n{}",
+            if failures.len() == 1 { "" } else { "s" },
+            failures.join("\n")
+        );
+    }
+
+    Ok(())
+}
+
+/// Collects every entry underneath `dir` (depth-first), alongside whether each one is a
+/// directory. Entries whose path relative to `root` matches any of `patterns` are pruned — the
+/// whole subtree is skipped rather than just the matched entry itself, so `--exclude secret`
+/// leaves `secret/` and everything below it untouched. Symlinks are skipped entirely (and never
+/// descended into) so that a symlink planted inside the tree can't redirect a privileged
+/// chmod/chown onto a target outside of it.
+// Unreadable subdirectories/entries are recorded in `failures` and skipped rather than
+// aborting the whole walk, so a single permission error doesn't stop `--recursive` from
+// applying to every other path it can reach (matching `apply_recursive`'s own apply-phase).
+fn collect_entries_recursive(
+    dir: &Utf8Path,
+    root: &Utf8Path,
+    patterns: &[Pattern],
+    out: &mut Vec<(Utf8PathBuf, bool)>,
+    failures: &mut Vec<String>,
+) {
+    let read_dir = match std::fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(err) => {
+            failures.push(format!("{dir}: failed to read directory: {err}"));
+            return;
+        }
+    };
+
+    for entry in read_dir {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                failures.push(format!("{dir}: failed to read entry: {err}"));
+                continue;
+            }
+        };
+
+        let path: Utf8PathBuf = match entry.path().try_into() {
+            Ok(path) => path,
+            Err(_) => {
+                failures.push(format!(
+                    "{}: non-UTF-8 path encountered while walking directory tree",
+                    entry.path().display()
+                ));
+                continue;
+            }
+        };
+
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        if patterns.iter().any(|pattern| pattern.matches(relative.as_str())) {
+            continue;
+        }
+
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(err) => {
+                failures.push(format!("{path}: failed to stat: {err}"));
+                continue;
+            }
+        };
+
+        if file_type.is_symlink() {
+            continue;
+        }
+
+        let is_dir = file_type.is_dir();
+        out.push((path.clone(), is_dir));
+
+        if is_dir {
+            collect_entries_recursive(&path, root, patterns, out, failures);
+        }
+    }
+}
+
+/// Applies `mode_spec`/`uid`/`gid` to a single path, treating `mode_spec`'s symbolic `X` as
+/// execute-if-directory-or-already-executable, matching regular `chmod` semantics.
+fn apply_owner_and_mode(
+    path: &Utf8Path,
+    is_dir: bool,
+    mode_spec: Option<&str>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> anyhow::Result<()> {
+    if let Some(spec) = mode_spec {
+        let existing_mode = std::fs::metadata(path)?.permissions().mode();
+        let mode = parse_mode(spec, existing_mode, is_dir)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("failed to set permissions {mode:o} on '{path}'"))?;
+    }
+
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::chown(path, uid, gid)
+            .with_context(|| format!("failed to change ownership {uid:?}:{gid:?} on '{path}'"))?;
+    }
+
+    Ok(())
+}
+
 pub fn run(args: Args) -> anyhow::Result<()> {
     log_init(
         "tedge-write",
@@ -66,7 +449,7 @@ pub fn run(args: Args) -> anyhow::Result<()> {
     }
 
     // unwrap is safe because clean returns an utf8 path when given an utf8 path
-    let target_filepath: Utf8PathBuf = path_clean::clean(args.destination_path.as_std_path())
+    let mut target_filepath: Utf8PathBuf = path_clean::clean(args.destination_path.as_std_path())
         .try_into()
         .unwrap();
 
@@ -77,11 +460,6 @@ pub fn run(args: Args) -> anyhow::Result<()> {
         );
     }
 
-    let mode = args
-        .mode
-        .map(|m| u32::from_str_radix(&m, 8).with_context(|| format!("invalid mode: {m}")))
-        .transpose()?;
-
     let uid = args
         .user
         .map(|u| uzers::get_user_by_name(&*u).with_context(|| format!("no such user: '{u}'")))
@@ -94,8 +472,40 @@ pub fn run(args: Args) -> anyhow::Result<()> {
         .transpose()?
         .map(|g| g.gid());
 
+    if args.recursive {
+        return apply_recursive(
+            &target_filepath,
+            args.mode.as_deref(),
+            uid,
+            gid,
+            &args.exclude,
+        );
+    }
+
+    target_filepath = refuse_non_regular_destination(target_filepath, args.follow_symlinks)?;
+
+    if let Some(trust_uid) = args.trust_uid {
+        verify_ancestors_trusted(&target_filepath, trust_uid, args.trust_gid)?;
+    }
+
+    let mode = args
+        .mode
+        .map(|m| {
+            let existing_mode = std::fs::metadata(&target_filepath)
+                .map(|meta| meta.permissions().mode())
+                .unwrap_or(0);
+            parse_mode(&m, existing_mode, false)
+        })
+        .transpose()?;
+
     if args.makedirs {
-        let dir = target_filepath.parent().unwrap();       
+        let dir_mode = args
+            .dir_mode
+            .map(|m| parse_mode(&m, 0o755, true))
+            .transpose()?
+            .unwrap_or(0o755);
+
+        let dir = target_filepath.parent().unwrap();
         if !dir.exists() {
 
             let mut current = Utf8PathBuf::new();
@@ -109,10 +519,9 @@ pub fn run(args: Args) -> anyhow::Result<()> {
                 std::fs::create_dir(&current)
                     .context(format!("failed to create directory '{current:?}'"))?;
 
-                let mode = 0o755;    // owner can do all, group, others can enter/read
-                let perm = std::fs::Permissions::from_mode(mode);
+                let perm = std::fs::Permissions::from_mode(dir_mode);
                 std::fs::set_permissions(&current, perm)
-                    .context(format!("failed to set permissions {mode:o} on directory '{current:?}'"))?;
+                    .context(format!("failed to set permissions {dir_mode:o} on directory '{current:?}'"))?;
 
                 if uid.is_some() || gid.is_some() {
                     std::os::unix::fs::chown(&current, uid, gid)
@@ -127,6 +536,12 @@ pub fn run(args: Args) -> anyhow::Result<()> {
 
     let src = std::io::stdin().lock();
 
+    if args.append {
+        append(src, &target_filepath, &permissions)
+            .with_context(|| format!("failed to append to destination file '{target_filepath}'"))?;
+        return Ok(());
+    }
+
     tedge_utils::atomic::write_file_atomic_set_permissions_if_doesnt_exist(
         src,
         &target_filepath,
@@ -137,3 +552,342 @@ pub fn run(args: Args) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Appends `src` to `target_filepath`, creating it with `permissions` if it doesn't already
+/// exist. Unlike the atomic truncate-and-replace path used for a regular write, owner/mode are
+/// only applied when the file is newly created, matching the "unchanged if it already exists"
+/// contract the rest of `tedge-write` follows.
+fn append(
+    mut src: impl std::io::Read,
+    target_filepath: &Utf8Path,
+    permissions: &MaybePermissions,
+) -> anyhow::Result<()> {
+    let existed = target_filepath.exists();
+
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(target_filepath)
+        .with_context(|| format!("failed to open '{target_filepath}'"))?;
+
+    std::io::copy(&mut src, &mut file)
+        .with_context(|| format!("failed to write to '{target_filepath}'"))?;
+
+    if !existed {
+        if let Some(mode) = permissions.mode {
+            std::fs::set_permissions(target_filepath, std::fs::Permissions::from_mode(mode))
+                .with_context(|| format!("failed to set permissions {mode:o} on '{target_filepath}'"))?;
+        }
+
+        if permissions.uid.is_some() || permissions.gid.is_some() {
+            std::os::unix::fs::chown(target_filepath, permissions.uid, permissions.gid)
+                .with_context(|| {
+                    format!(
+                        "failed to change ownership {:?}:{:?} on '{target_filepath}'",
+                        permissions.uid, permissions.gid
+                    )
+                })?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_mode_accepts_octal() {
+        assert_eq!(parse_mode("644", 0, false).unwrap(), 0o644);
+        assert_eq!(parse_mode("0755", 0, false).unwrap(), 0o755);
+    }
+
+    #[test]
+    fn parse_mode_symbolic_assign_ignores_base() {
+        assert_eq!(parse_mode("u=rw,g=r,o=", 0o777, false).unwrap(), 0o640);
+    }
+
+    #[test]
+    fn parse_mode_symbolic_incremental_add_and_remove() {
+        assert_eq!(parse_mode("u+x,g-w", 0o644, false).unwrap(), 0o744 & !0o020);
+    }
+
+    #[test]
+    fn parse_mode_symbolic_who_a_applies_to_all() {
+        assert_eq!(parse_mode("a=rx", 0o777, false).unwrap(), 0o555);
+    }
+
+    #[test]
+    fn parse_mode_symbolic_who_defaults_to_all() {
+        assert_eq!(parse_mode("=rw", 0, false).unwrap(), 0o666);
+    }
+
+    #[test]
+    fn parse_mode_symbolic_capital_x_sets_execute_for_directories() {
+        assert_eq!(parse_mode("u=rwX", 0, true).unwrap() & 0o100, 0o100);
+    }
+
+    #[test]
+    fn parse_mode_symbolic_capital_x_skips_execute_for_files_without_exec_bit() {
+        assert_eq!(parse_mode("u=rwX", 0, false).unwrap() & 0o100, 0);
+    }
+
+    #[test]
+    fn parse_mode_symbolic_capital_x_keeps_execute_if_already_set() {
+        // the group already has execute, so `X` on `other` should also set execute
+        assert_eq!(parse_mode("o=rX", 0o010, false).unwrap() & 0o001, 0o001);
+    }
+
+    #[test]
+    fn parse_mode_rejects_invalid_spec() {
+        assert!(parse_mode("u=rwq", 0, false).is_err());
+        assert!(parse_mode("not-a-mode", 0, false).is_err());
+    }
+
+    fn own_uid() -> u32 {
+        uzers::get_current_uid()
+    }
+
+    #[test]
+    fn verify_ancestors_trusted_accepts_own_uid() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = Utf8Path::from_path(dir.path()).unwrap().join("file");
+
+        verify_ancestors_trusted(&target, own_uid(), None).unwrap();
+    }
+
+    #[test]
+    fn verify_ancestors_trusted_rejects_world_writable_ancestor() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir_path = Utf8Path::from_path(dir.path()).unwrap();
+        std::fs::set_permissions(dir_path, std::fs::Permissions::from_mode(0o777)).unwrap();
+        let target = dir_path.join("file");
+
+        let err = verify_ancestors_trusted(&target, own_uid(), None).unwrap_err();
+        assert!(err.to_string().contains("bad permissions"));
+    }
+
+    #[test]
+    fn verify_ancestors_trusted_exempts_sticky_world_writable_ancestor() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir_path = Utf8Path::from_path(dir.path()).unwrap();
+        // Sticky + world-writable, like /tmp: anyone can create files there, but not
+        // rename/delete ones they don't own, so it can't be used to swap out this ancestor.
+        std::fs::set_permissions(dir_path, std::fs::Permissions::from_mode(0o1777)).unwrap();
+        let target = dir_path.join("file");
+
+        verify_ancestors_trusted(&target, own_uid(), None).unwrap();
+    }
+
+    #[test]
+    fn verify_ancestors_trusted_allows_not_yet_created_makedirs_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = Utf8Path::from_path(dir.path())
+            .unwrap()
+            .join("not-yet-created")
+            .join("file");
+
+        // The immediate parent doesn't exist yet, as when it'll be created by --makedirs: the
+        // walk must stop there instead of failing to stat it.
+        verify_ancestors_trusted(&target, own_uid(), None).unwrap();
+    }
+
+    #[test]
+    fn verify_ancestors_trusted_rejects_untrusted_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = Utf8Path::from_path(dir.path()).unwrap().join("file");
+
+        // No real system is guaranteed to have this uid unused, but it's extremely unlikely to
+        // be the uid running this test. `verify_ancestors_trusted` always trusts uid 0 as well,
+        // so this only reports a bad owner when the test is not run as root.
+        let untrusted_uid = own_uid().wrapping_add(12345);
+
+        let err = verify_ancestors_trusted(&target, untrusted_uid, None).unwrap_err();
+        assert!(err.to_string().contains("bad owner"));
+    }
+
+    #[test]
+    fn apply_recursive_prunes_excluded_subtree() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(dir.path()).unwrap();
+        std::fs::create_dir(root.join("secret")).unwrap();
+        std::fs::write(root.join("secret/inner"), b"hi").unwrap();
+        std::fs::write(root.join("kept"), b"hi").unwrap();
+
+        apply_recursive(root, Some("700"), None, None, &["secret".to_string()]).unwrap();
+
+        let secret_mode = std::fs::metadata(root.join("secret"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777;
+        let inner_mode = std::fs::metadata(root.join("secret/inner"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777;
+        let kept_mode = std::fs::metadata(root.join("kept"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777;
+
+        assert_ne!(secret_mode, 0o700, "excluded directory must be skipped");
+        assert_ne!(
+            inner_mode, 0o700,
+            "children of an excluded directory must be skipped too"
+        );
+        assert_eq!(kept_mode, 0o700);
+    }
+
+    #[test]
+    fn apply_recursive_skips_symlinks() {
+        // the symlink's target lives outside of `root` so that it can only be affected through
+        // the symlink being dereferenced, never by being walked directly
+        let outside = tempfile::tempdir().unwrap();
+        let target = Utf8Path::from_path(outside.path()).unwrap().join("target");
+        std::fs::write(&target, b"hi").unwrap();
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(dir.path()).unwrap();
+        std::os::unix::fs::symlink(&target, root.join("link")).unwrap();
+
+        apply_recursive(root, Some("700"), None, None, &[]).unwrap();
+
+        let target_mode = std::fs::metadata(&target).unwrap().permissions().mode() & 0o777;
+        assert_eq!(
+            target_mode, 0o644,
+            "chmod through the symlink must not affect its target"
+        );
+    }
+
+    #[test]
+    fn collect_entries_recursive_skips_unreadable_subtree_but_keeps_other_results() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(dir.path()).unwrap();
+        std::fs::create_dir(root.join("ok")).unwrap();
+        std::fs::write(root.join("ok/inner"), b"hi").unwrap();
+
+        let mut out = Vec::new();
+        let mut failures = Vec::new();
+
+        // a subtree that can never be read, as if a permission error had been hit partway
+        // through the walk
+        collect_entries_recursive(
+            &root.join("does-not-exist"),
+            root,
+            &[],
+            &mut out,
+            &mut failures,
+        );
+        // a sibling subtree that reads fine: its results must not be lost because of the
+        // failure above
+        collect_entries_recursive(&root.join("ok"), root, &[], &mut out, &mut failures);
+
+        assert_eq!(failures.len(), 1, "the missing subtree must be reported");
+        assert!(failures[0].contains("does-not-exist"));
+        assert_eq!(out, vec![(root.join("ok/inner"), false)]);
+    }
+
+    #[test]
+    fn append_creates_new_file_with_permissions_and_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = Utf8Path::from_path(dir.path()).unwrap().join("file");
+        let permissions = MaybePermissions {
+            uid: None,
+            gid: None,
+            mode: Some(0o640),
+        };
+
+        append(&b"hello"[..], &target, &permissions).unwrap();
+
+        let mode = std::fs::metadata(&target).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o640);
+        assert_eq!(std::fs::read(&target).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn append_leaves_existing_permissions_unchanged_and_appends_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = Utf8Path::from_path(dir.path()).unwrap().join("file");
+        std::fs::write(&target, b"existing-").unwrap();
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        let permissions = MaybePermissions {
+            uid: None,
+            gid: None,
+            mode: Some(0o777),
+        };
+        append(&b"appended"[..], &target, &permissions).unwrap();
+
+        let mode = std::fs::metadata(&target).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o600);
+        assert_eq!(std::fs::read(&target).unwrap(), b"existing-appended");
+    }
+
+    #[test]
+    fn refuse_non_regular_destination_allows_missing_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = Utf8Path::from_path(dir.path()).unwrap().join("missing");
+
+        let result = refuse_non_regular_destination(missing.clone(), false).unwrap();
+        assert_eq!(result, missing);
+    }
+
+    #[test]
+    fn refuse_non_regular_destination_allows_existing_regular_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = Utf8Path::from_path(dir.path()).unwrap().join("file");
+        std::fs::write(&file, b"hi").unwrap();
+
+        let result = refuse_non_regular_destination(file.clone(), false).unwrap();
+        assert_eq!(result, file);
+    }
+
+    #[test]
+    fn refuse_non_regular_destination_rejects_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(dir.path()).unwrap().to_path_buf();
+
+        assert!(refuse_non_regular_destination(root, false).is_err());
+    }
+
+    #[test]
+    fn refuse_non_regular_destination_rejects_symlink_without_follow_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(dir.path()).unwrap();
+        let file = root.join("file");
+        std::fs::write(&file, b"hi").unwrap();
+        let link = root.join("link");
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+
+        assert!(refuse_non_regular_destination(link, false).is_err());
+    }
+
+    #[test]
+    fn refuse_non_regular_destination_follow_symlinks_resolves_to_regular_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(dir.path()).unwrap();
+        let file = root.join("file");
+        std::fs::write(&file, b"hi").unwrap();
+        let link = root.join("link");
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+
+        let resolved = refuse_non_regular_destination(link, true).unwrap();
+        assert_eq!(resolved, std::fs::canonicalize(&file).unwrap());
+    }
+
+    #[test]
+    fn refuse_non_regular_destination_follow_symlinks_rechecks_resolved_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(dir.path()).unwrap();
+        let link = root.join("link");
+        // the symlink resolves to a directory, not a regular file, so the re-check after
+        // following it must still reject it
+        std::os::unix::fs::symlink(root, &link).unwrap();
+
+        assert!(refuse_non_regular_destination(link, true).is_err());
+    }
+}
+